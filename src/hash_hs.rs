@@ -1,9 +1,10 @@
 extern crate ring;
-use self::ring::digest;
+use self::ring::{digest, hmac};
 
 use std::mem;
 use msgs::codec::Codec;
 use msgs::message::{Message, MessagePayload};
+use key_schedule::hkdf_expand_label;
 
 /// This deals with keeping a running hash of the handshake
 /// payloads.  This is computed by buffering initially.  Once
@@ -73,6 +74,75 @@ impl HandshakeHash {
     self
   }
 
+  /// Hash a handshake message, but only include it in the transcript
+  /// up to `binder_offset` bytes into its encoding.  This computes
+  /// `Transcript-Hash(Truncate(ClientHello))`, as required to verify
+  /// or produce PSK binders (RFC8446 section 4.2.11.2), without
+  /// disturbing the running transcript used for the rest of the
+  /// handshake: it hashes into a clone of `ctx` and discards it.
+  pub fn get_hash_given_client_hello_truncated(&self, ch: &Message, binder_offset: usize) -> Vec<u8> {
+    let mut ctx = self.ctx.as_ref().unwrap().clone();
+    let mut buf = Vec::new();
+
+    match ch.payload {
+      MessagePayload::Handshake(ref hs) => hs.encode(&mut buf),
+      _ => unreachable!()
+    };
+
+    ctx.update(&buf[..binder_offset]);
+
+    let h = ctx.finish();
+    let mut ret = Vec::new();
+    ret.extend_from_slice(h.as_ref());
+    ret
+  }
+
+  /// Compute the Finished `verify_data` for `base_key`, over the
+  /// transcript as it stands right now:
+  /// `HMAC(HKDF-Expand-Label(base_key, "finished", "", Hash.len), get_current_hash())`
+  /// (RFC8446 section 4.4.4).
+  pub fn sign_verify_data(&self, base_key: &[u8]) -> Vec<u8> {
+    self.sign_verify_data_over(base_key, &self.get_current_hash())
+  }
+
+  /// Compute a PSK binder or Finished `verify_data` for `base_key`
+  /// over an arbitrary transcript hash: `HMAC(HKDF-Expand-Label(base_key,
+  /// "finished", "", Hash.len), hash)` (RFC8446 section 4.4.4).  PSK
+  /// binders use this directly with the truncated hash produced by
+  /// `get_hash_given_client_hello_truncated`, since by the time the
+  /// binder is signed the live transcript has moved past ClientHello.
+  pub fn sign_verify_data_over(&self, base_key: &[u8], hash: &[u8]) -> Vec<u8> {
+    let hash_alg = self.algorithm();
+    let finished_key = hkdf_expand_label(hash_alg, base_key, b"finished", &[], hash_alg.output_len);
+    let key = hmac::SigningKey::new(hash_alg, &finished_key);
+    hmac::sign(&key, hash).as_ref().to_vec()
+  }
+
+  /// Rewrite the transcript on receipt of a HelloRetryRequest
+  /// (RFC8446 section 4.4.1): the first ClientHello is removed and
+  /// replaced with a synthetic `message_hash` message wrapping
+  /// `Hash(ClientHello1)`, i.e. the 4-byte header
+  /// `[0xfe, 0x00, 0x00, Hash.len]` followed by the digest itself.
+  /// Normal `add_message` calls (for the HelloRetryRequest and
+  /// ClientHello2 onwards) resume on top of this synthetic message.
+  pub fn rollup_for_hrr(&mut self) {
+    assert!(self.ctx.is_some());
+
+    let old_hash = self.get_current_hash();
+    let alg = self.algorithm();
+
+    let mut synthetic = vec![0xfe, 0x00, 0x00, old_hash.len() as u8];
+    synthetic.extend_from_slice(&old_hash);
+
+    let mut ctx = digest::Context::new(alg);
+    ctx.update(&synthetic);
+    self.ctx = Some(ctx);
+
+    if self.client_auth_enabled {
+      self.buffer = synthetic;
+    }
+  }
+
   /// Hash or buffer a byte slice.
   fn update_raw(&mut self, buf: &[u8]) -> &mut Self {
     if self.ctx.is_some() {
@@ -94,6 +164,16 @@ impl HandshakeHash {
     ret
   }
 
+  /// Get the hash algorithm in use, once `start_hash` has been called.
+  pub fn algorithm(&self) -> &'static digest::Algorithm {
+    self.ctx.as_ref().unwrap().algorithm()
+  }
+
+  /// Get the length in bytes of the digest produced by `get_current_hash`.
+  pub fn output_len(&self) -> usize {
+    self.algorithm().output_len
+  }
+
   /// Takes this object's buffer containing all handshake messages
   /// so far.  This method only works once; it resets the buffer
   /// to empty.
@@ -160,4 +240,21 @@ mod test {
     assert_eq!(h[2], 0x18);
     assert_eq!(h[3], 0x5c);
   }
+
+  #[test]
+  fn rollup_for_hrr_replaces_transcript_with_message_hash() {
+    let mut hh = HandshakeHash::new();
+    hh.set_client_auth_enabled();
+    hh.update_raw(b"clienthello1");
+    hh.start_hash(&ring::digest::SHA256);
+    hh.rollup_for_hrr();
+
+    let ch1_hash = ring::digest::digest(&ring::digest::SHA256, b"clienthello1");
+    let mut synthetic = vec![0xfe, 0x00, 0x00, ch1_hash.as_ref().len() as u8];
+    synthetic.extend_from_slice(ch1_hash.as_ref());
+    let expected = ring::digest::digest(&ring::digest::SHA256, &synthetic);
+
+    assert_eq!(hh.get_current_hash(), expected.as_ref().to_vec());
+    assert_eq!(hh.take_handshake_buf(), synthetic);
+  }
 }
\ No newline at end of file
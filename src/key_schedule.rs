@@ -0,0 +1,211 @@
+extern crate ring;
+use self::ring::{digest, hmac};
+
+use msgs::codec::Codec;
+use hash_hs::HandshakeHash;
+
+/// HKDF-Extract, as defined in RFC5869 section 2.2.
+fn hkdf_extract(alg: &'static digest::Algorithm, salt: &[u8], ikm: &[u8]) -> Vec<u8> {
+  let key = hmac::SigningKey::new(alg, salt);
+  hmac::sign(&key, ikm).as_ref().to_vec()
+}
+
+/// HKDF-Expand, as defined in RFC5869 section 2.3.
+fn hkdf_expand(alg: &'static digest::Algorithm, secret: &[u8], info: &[u8], output_len: usize) -> Vec<u8> {
+  let key = hmac::SigningKey::new(alg, secret);
+  let mut output = Vec::new();
+  let mut t = Vec::new();
+  let mut i: u8 = 0;
+
+  while output.len() < output_len {
+    i += 1;
+    let mut ctx = hmac::SigningContext::with_key(&key);
+    ctx.update(&t);
+    ctx.update(info);
+    ctx.update(&[i]);
+    t = ctx.sign().as_ref().to_vec();
+    output.extend_from_slice(&t);
+  }
+
+  output.truncate(output_len);
+  output
+}
+
+/// The `HkdfLabel` structure from RFC8446 section 7.1.
+struct HkdfLabel {
+  length: u16,
+  label: Vec<u8>,
+  context: Vec<u8>
+}
+
+impl HkdfLabel {
+  fn encode(&self) -> Vec<u8> {
+    let mut ret = Vec::new();
+    self.length.encode(&mut ret);
+    (self.label.len() as u8).encode(&mut ret);
+    ret.extend_from_slice(&self.label);
+    (self.context.len() as u8).encode(&mut ret);
+    ret.extend_from_slice(&self.context);
+    ret
+  }
+}
+
+/// `HKDF-Expand-Label(Secret, Label, Context, Length)`, as defined
+/// in RFC8446 section 7.1.
+pub fn hkdf_expand_label(alg: &'static digest::Algorithm,
+                          secret: &[u8],
+                          label: &[u8],
+                          context: &[u8],
+                          output_len: usize) -> Vec<u8> {
+  let mut full_label = b"tls13 ".to_vec();
+  full_label.extend_from_slice(label);
+
+  let info = HkdfLabel {
+    length: output_len as u16,
+    label: full_label,
+    context: context.to_vec()
+  }.encode();
+
+  hkdf_expand(alg, secret, &info, output_len)
+}
+
+/// `Derive-Secret(Secret, Label, Messages)`, as defined in
+/// RFC8446 section 7.1, where `Messages` is the transcript held
+/// by `hs` at the point of the call.
+fn derive_secret(alg: &'static digest::Algorithm,
+                  secret: &[u8],
+                  label: &[u8],
+                  hs: &HandshakeHash) -> Vec<u8> {
+  hkdf_expand_label(alg, secret, label, &hs.get_current_hash(), alg.output_len)
+}
+
+/// `Transcript-Hash("")`: the digest of the empty string.  The
+/// `"derived"` step of the key-schedule ladder always uses this as
+/// its `Messages` context -- never the live handshake transcript --
+/// per RFC8446 section 7.1.
+fn empty_hash(alg: &'static digest::Algorithm) -> Vec<u8> {
+  digest::digest(alg, &[]).as_ref().to_vec()
+}
+
+/// `Derive-Secret(Secret, "derived", "")`, used to salt the
+/// `HKDF-Extract` that moves to the next secret in the ladder.
+fn derive_secret_for_next_extract(alg: &'static digest::Algorithm, secret: &[u8]) -> Vec<u8> {
+  hkdf_expand_label(alg, secret, b"derived", &empty_hash(alg), alg.output_len)
+}
+
+/// Drives the TLS 1.3 key-schedule ladder (RFC8446 section 7.1):
+/// Early Secret, Handshake Secret and Master Secret, each reachable
+/// from the last via `Derive-Secret(., "derived", "")` followed by
+/// `HKDF-Extract`.
+pub struct KeySchedule {
+  algorithm: &'static digest::Algorithm,
+  current: Vec<u8>
+}
+
+impl KeySchedule {
+  /// Start a new key schedule, with Early Secret keyed off `psk`
+  /// (all-zeroes if there is no PSK in use).
+  pub fn new(algorithm: &'static digest::Algorithm, psk: &[u8]) -> KeySchedule {
+    let zeroes = vec![0u8; algorithm.output_len];
+    KeySchedule {
+      algorithm: algorithm,
+      current: hkdf_extract(algorithm, &zeroes, psk)
+    }
+  }
+
+  /// Move from the Early Secret to the Handshake Secret, mixing in
+  /// the (EC)DHE shared secret.
+  pub fn input_handshake_secret(&mut self, ecdhe: &[u8]) {
+    let salt = derive_secret_for_next_extract(self.algorithm, &self.current);
+    self.current = hkdf_extract(self.algorithm, &salt, ecdhe);
+  }
+
+  /// Move from the Handshake Secret to the Master Secret.
+  pub fn input_master_secret(&mut self) {
+    let salt = derive_secret_for_next_extract(self.algorithm, &self.current);
+    let zeroes = vec![0u8; self.algorithm.output_len];
+    self.current = hkdf_extract(self.algorithm, &salt, &zeroes);
+  }
+
+  /// `Derive-Secret(Early Secret, "c e traffic", ClientHello)`.
+  pub fn client_early_traffic_secret(&self, hs: &HandshakeHash) -> Vec<u8> {
+    derive_secret(self.algorithm, &self.current, b"c e traffic", hs)
+  }
+
+  /// `Derive-Secret(Handshake Secret, "c hs traffic", ClientHello..ServerHello)`.
+  pub fn client_handshake_traffic_secret(&self, hs: &HandshakeHash) -> Vec<u8> {
+    derive_secret(self.algorithm, &self.current, b"c hs traffic", hs)
+  }
+
+  /// `Derive-Secret(Handshake Secret, "s hs traffic", ClientHello..ServerHello)`.
+  pub fn server_handshake_traffic_secret(&self, hs: &HandshakeHash) -> Vec<u8> {
+    derive_secret(self.algorithm, &self.current, b"s hs traffic", hs)
+  }
+
+  /// `Derive-Secret(Master Secret, "c ap traffic", ClientHello..server Finished)`.
+  pub fn client_application_traffic_secret(&self, hs: &HandshakeHash) -> Vec<u8> {
+    derive_secret(self.algorithm, &self.current, b"c ap traffic", hs)
+  }
+
+  /// `Derive-Secret(Master Secret, "s ap traffic", ClientHello..server Finished)`.
+  pub fn server_application_traffic_secret(&self, hs: &HandshakeHash) -> Vec<u8> {
+    derive_secret(self.algorithm, &self.current, b"s ap traffic", hs)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{hkdf_extract, hkdf_expand, KeySchedule};
+  use super::ring::digest;
+
+  #[test]
+  fn hkdf_rfc5869_case1() {
+    // RFC5869 Appendix A.1 (Basic test case with SHA-256).
+    let ikm = [0x0b; 22];
+    let salt = (0x00..0x0d).collect::<Vec<u8>>();
+    let info = (0xf0..0xfa).collect::<Vec<u8>>();
+
+    let prk = hkdf_extract(&digest::SHA256, &salt, &ikm);
+    let expected_prk = [
+      0x07, 0x77, 0x09, 0x36, 0x2c, 0x2e, 0x32, 0xdf, 0x0d, 0xdc, 0x3f, 0x0d, 0xc4, 0x7b, 0xba, 0x63,
+      0x90, 0xb6, 0xc7, 0x3b, 0xb5, 0x0f, 0x9c, 0x31, 0x22, 0xec, 0x84, 0x4a, 0xd7, 0xc2, 0xb3, 0xe5
+    ];
+    assert_eq!(prk, expected_prk.to_vec());
+
+    let okm = hkdf_expand(&digest::SHA256, &prk, &info, 42);
+    let expected_okm = [
+      0x3c, 0xb2, 0x5f, 0x25, 0xfa, 0xac, 0xd5, 0x7a, 0x90, 0x43, 0x4f, 0x64, 0xd0, 0x36, 0x2f, 0x2a,
+      0x2d, 0x2d, 0x0a, 0x90, 0xcf, 0x1a, 0x5a, 0x4c, 0x5d, 0xb0, 0x2d, 0x56, 0xec, 0xc4, 0xc5, 0xbf,
+      0x34, 0x00, 0x72, 0x08, 0xd5, 0xb8, 0x87, 0x18, 0x58, 0x65
+    ];
+    assert_eq!(okm, expected_okm.to_vec());
+  }
+
+  #[test]
+  fn rfc8448_early_and_handshake_secrets() {
+    // RFC8448 section 3 ("Simple 1-RTT Handshake"): the Early Secret
+    // (no PSK in use) and the Handshake Secret derived from it via
+    // `Derive-Secret(Early Secret, "derived", "")` followed by
+    // `HKDF-Extract` with the (EC)DHE shared secret.  This exercises
+    // the fix for the "derived" step using `Transcript-Hash("")`
+    // rather than the live handshake transcript.
+    let ecdhe_shared_secret = [
+      0x8b, 0xd4, 0x05, 0x4f, 0xb5, 0x5b, 0x9d, 0x63, 0xfd, 0xfb, 0xac, 0xf9, 0xf0, 0x4b, 0x9f, 0x0d,
+      0x35, 0xe6, 0xd6, 0x3f, 0x53, 0x75, 0x63, 0xef, 0xd4, 0x62, 0x72, 0x90, 0x0f, 0x89, 0x49, 0x2d
+    ];
+
+    let mut ks = KeySchedule::new(&digest::SHA256, &[0u8; 32]);
+    let expected_early_secret = [
+      0x33, 0xad, 0x0a, 0x1c, 0x60, 0x7e, 0xc0, 0x3b, 0x09, 0xe6, 0xcd, 0x98, 0x93, 0x68, 0x0c, 0xe2,
+      0x10, 0xad, 0xf3, 0x00, 0xaa, 0x1f, 0x26, 0x60, 0xe1, 0xb2, 0x2e, 0x10, 0xf1, 0x70, 0xf9, 0x2a
+    ];
+    assert_eq!(ks.current, expected_early_secret.to_vec());
+
+    ks.input_handshake_secret(&ecdhe_shared_secret);
+    let expected_handshake_secret = [
+      0x1d, 0xc8, 0x26, 0xe9, 0x36, 0x06, 0xaa, 0x6f, 0xdc, 0x0a, 0xad, 0xc1, 0x2f, 0x74, 0x1b, 0x01,
+      0x04, 0x6a, 0xa6, 0xb9, 0x9f, 0x69, 0x1e, 0xd2, 0x21, 0xa9, 0xf0, 0xca, 0x04, 0x3f, 0xbe, 0xac
+    ];
+    assert_eq!(ks.current, expected_handshake_secret.to_vec());
+  }
+}
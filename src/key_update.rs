@@ -0,0 +1,194 @@
+extern crate ring;
+use self::ring::digest;
+
+use std::mem;
+use key_schedule::hkdf_expand_label;
+
+/// A conservative invocation limit enforced well under any cipher's
+/// hard usage limit (e.g. 2^35 for AES-GCM, RFC8446 section 5.5), so
+/// we always ratchet with plenty of headroom to spare.
+const INVOCATION_LIMIT_THRESHOLD: u64 = 1 << 20;
+
+/// Overwrite `buf` with zeroes, so a superseded secret/key/iv does
+/// not linger in memory once it's been ratcheted away.
+fn zero(buf: &mut [u8]) {
+  for b in buf.iter_mut() {
+    *b = 0;
+  }
+}
+
+/// One traffic direction's current secret/key/iv, plus the number of
+/// AEAD invocations remaining before we must ratchet.  Modelled on
+/// neqo's per-direction cipher-usage tracking.
+struct DirectionKeys {
+  secret: Vec<u8>,
+  key: Vec<u8>,
+  iv: Vec<u8>,
+  remaining_invocations: u64
+}
+
+impl DirectionKeys {
+  fn new(algorithm: &'static digest::Algorithm,
+         secret: Vec<u8>,
+         key_len: usize,
+         iv_len: usize) -> DirectionKeys {
+    let key = hkdf_expand_label(algorithm, &secret, b"key", &[], key_len);
+    let iv = hkdf_expand_label(algorithm, &secret, b"iv", &[], iv_len);
+    DirectionKeys {
+      secret: secret,
+      key: key,
+      iv: iv,
+      remaining_invocations: INVOCATION_LIMIT_THRESHOLD
+    }
+  }
+
+  /// Ratchet to `application_traffic_secret_{N+1}` (RFC8446 section
+  /// 7.2) and re-derive this direction's key and IV, zeroing the superseded
+  /// secret/key/iv.
+  fn update(&mut self, algorithm: &'static digest::Algorithm, key_len: usize, iv_len: usize) {
+    let next_secret = hkdf_expand_label(algorithm, &self.secret, b"traffic upd", &[], self.secret.len());
+    let mut old = mem::replace(self, DirectionKeys::new(algorithm, next_secret, key_len, iv_len));
+    zero(&mut old.secret);
+    zero(&mut old.key);
+    zero(&mut old.iv);
+  }
+}
+
+/// Post-handshake application traffic key updates (RFC8446 section
+/// 4.6.3): tracks both directions' keys, reports when the write side's
+/// invocation counter runs out, and ratchets the read side when the
+/// peer sends a KeyUpdate (reporting whether a reciprocal update was
+/// requested).  It does not send a KeyUpdate or ratchet the write side
+/// on its own: the caller sends the KeyUpdate and then calls
+/// `update_write`.
+pub struct KeyUpdater {
+  algorithm: &'static digest::Algorithm,
+  key_len: usize,
+  iv_len: usize,
+  write: DirectionKeys,
+  read: DirectionKeys
+}
+
+impl KeyUpdater {
+  pub fn new(algorithm: &'static digest::Algorithm,
+             key_len: usize,
+             iv_len: usize,
+             client_application_traffic_secret: Vec<u8>,
+             server_application_traffic_secret: Vec<u8>,
+             we_are_client: bool) -> KeyUpdater {
+    let (write_secret, read_secret) = if we_are_client {
+      (client_application_traffic_secret, server_application_traffic_secret)
+    } else {
+      (server_application_traffic_secret, client_application_traffic_secret)
+    };
+
+    KeyUpdater {
+      algorithm: algorithm,
+      key_len: key_len,
+      iv_len: iv_len,
+      write: DirectionKeys::new(algorithm, write_secret, key_len, iv_len),
+      read: DirectionKeys::new(algorithm, read_secret, key_len, iv_len)
+    }
+  }
+
+  pub fn write_key(&self) -> &[u8] {
+    &self.write.key
+  }
+
+  pub fn write_iv(&self) -> &[u8] {
+    &self.write.iv
+  }
+
+  pub fn read_key(&self) -> &[u8] {
+    &self.read.key
+  }
+
+  pub fn read_iv(&self) -> &[u8] {
+    &self.read.iv
+  }
+
+  /// Record one AEAD encryption with the current write key.  Returns
+  /// `true` if the write side has now run low on invocations and a
+  /// KeyUpdate must be sent.  That KeyUpdate has to go out under the
+  /// *current* write key -- the peer can't decrypt it otherwise -- so
+  /// the caller must write it first and only then call `update_write`
+  /// to ratchet.
+  pub fn count_write(&mut self) -> bool {
+    self.write.remaining_invocations -= 1;
+    self.write.remaining_invocations == 0
+  }
+
+  /// Ratchet the write-side secret/key/iv.  Call this once the
+  /// KeyUpdate record (self-initiated, or reciprocal to a peer's) has
+  /// already been written under the current write key.
+  pub fn update_write(&mut self) {
+    self.write.update(self.algorithm, self.key_len, self.iv_len);
+  }
+
+  /// Handle a KeyUpdate received from the peer: ratchet the read
+  /// secret immediately, and report whether `update_requested` was
+  /// set.  If so, the caller must send a reciprocal KeyUpdate under
+  /// the current write key and only then call `update_write` to
+  /// ratchet it -- ratcheting first would send that KeyUpdate under a
+  /// key the peer hasn't installed yet.
+  pub fn handle_key_update(&mut self, update_requested: bool) -> bool {
+    self.read.update(self.algorithm, self.key_len, self.iv_len);
+    update_requested
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{KeyUpdater, INVOCATION_LIMIT_THRESHOLD};
+  use super::ring::digest;
+  use key_schedule::hkdf_expand_label;
+
+  #[test]
+  fn signals_before_ratcheting_write_side_after_threshold_invocations() {
+    let algorithm = &digest::SHA256;
+    let secret = vec![0x42u8; algorithm.output_len];
+    let mut ku = KeyUpdater::new(algorithm, 16, 12, secret.clone(), secret.clone(), true);
+
+    for _ in 0..INVOCATION_LIMIT_THRESHOLD - 1 {
+      assert_eq!(ku.count_write(), false);
+    }
+
+    // The threshold is hit: the caller must still be able to write a
+    // KeyUpdate under the pre-ratchet key before we ratchet it away.
+    let write_key_before = ku.write_key().to_vec();
+    let write_iv_before = ku.write_iv().to_vec();
+    assert_eq!(ku.count_write(), true);
+    assert_eq!(ku.write_key().to_vec(), write_key_before);
+    assert_eq!(ku.write_iv().to_vec(), write_iv_before);
+
+    ku.update_write();
+    let expected_secret = hkdf_expand_label(algorithm, &secret, b"traffic upd", &[], algorithm.output_len);
+    let expected_key = hkdf_expand_label(algorithm, &expected_secret, b"key", &[], 16);
+    let expected_iv = hkdf_expand_label(algorithm, &expected_secret, b"iv", &[], 12);
+    assert_eq!(ku.write_key().to_vec(), expected_key);
+    assert_eq!(ku.write_iv().to_vec(), expected_iv);
+  }
+
+  #[test]
+  fn peer_key_update_ratchets_read_immediately_and_defers_reciprocal_write() {
+    let algorithm = &digest::SHA256;
+    let client_secret = vec![0x11u8; algorithm.output_len];
+    let server_secret = vec![0x22u8; algorithm.output_len];
+    let mut ku = KeyUpdater::new(algorithm, 16, 12, client_secret, server_secret.clone(), true);
+
+    let write_key_before = ku.write_key().to_vec();
+    let must_update_write = ku.handle_key_update(true);
+
+    let expected_read_secret = hkdf_expand_label(algorithm, &server_secret, b"traffic upd", &[], algorithm.output_len);
+    let expected_read_key = hkdf_expand_label(algorithm, &expected_read_secret, b"key", &[], 16);
+    assert_eq!(ku.read_key().to_vec(), expected_read_key);
+
+    // The reciprocal KeyUpdate is signalled, but the write side must
+    // not be ratcheted until the caller has written it.
+    assert_eq!(must_update_write, true);
+    assert_eq!(ku.write_key().to_vec(), write_key_before);
+
+    ku.update_write();
+    assert!(ku.write_key().to_vec() != write_key_before);
+  }
+}